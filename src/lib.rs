@@ -1,22 +1,147 @@
-use std::{future::Future, path::Path};
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use eyre::Result;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, ErrorCode, OpenFlags};
+use serde::{de::DeserializeOwned, Serialize};
+use thread_local::ThreadLocal;
+use tokio::sync::{broadcast, Mutex as AsyncMutex, Semaphore};
+
+/// Number of concurrent fetch/insert fan-outs allowed at once. Kept modest so
+/// a cache stampede on a cold cache doesn't open unbounded connections or
+/// hammer the database with writers.
+const MAX_CONCURRENT_WRITES: usize = 32;
+
+/// Number of times a write is retried if SQLite reports the database as
+/// locked by another connection before giving up.
+const MAX_LOCK_RETRIES: u32 = 5;
+
+/// Result broadcast to callers that arrived while a fetch for their key was
+/// already in flight. The error side is a rendered message rather than
+/// `eyre::Report`, since a `broadcast::Sender` requires a `Clone` value.
+type SharedFetchResult = Result<Arc<Vec<u8>>, Arc<str>>;
+
+/// Result broadcast to callers that arrived while a response fetch for
+/// their variant was already in flight.
+type SharedResponseResult = Result<Arc<(Vec<(String, String)>, Vec<u8>)>, Arc<str>>;
+
+/// A raw row read back from the `cached` table: its value, the time it was
+/// inserted (absent for rows written before TTL support existed), and its
+/// stored HTTP response headers, if any.
+type CachedRow = (Vec<u8>, Option<i64>, Option<Vec<u8>>);
 
 pub struct ResponseCache {
-    connection: Connection,
+    path: PathBuf,
+    connections: ThreadLocal<Connection>,
+    default_ttl: Option<Duration>,
+    write_permits: Semaphore,
+    in_flight: AsyncMutex<HashMap<String, broadcast::Sender<SharedFetchResult>>>,
+    response_in_flight: AsyncMutex<HashMap<String, broadcast::Sender<SharedResponseResult>>>,
+    cache_only: AtomicBool,
 }
 
 impl ResponseCache {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
-        let connection = rusqlite::Connection::open(path.as_ref())?;
+        Self::with_default_ttl(path, None)
+    }
+
+    /// Opens a cache that treats an entry older than `ttl` as a miss and
+    /// re-fetches it.
+    pub fn with_ttl(path: impl AsRef<Path>, ttl: Duration) -> Result<Self> {
+        Self::with_default_ttl(path, Some(ttl))
+    }
+
+    fn with_default_ttl(path: impl AsRef<Path>, default_ttl: Option<Duration>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        // Open once up front so schema errors and permission problems
+        // surface immediately, rather than on the first `get` call.
+        Self::open_connection(&path)?;
+
+        Ok(ResponseCache {
+            path,
+            connections: ThreadLocal::new(),
+            default_ttl,
+            write_permits: Semaphore::new(MAX_CONCURRENT_WRITES),
+            in_flight: AsyncMutex::new(HashMap::new()),
+            response_in_flight: AsyncMutex::new(HashMap::new()),
+            cache_only: AtomicBool::new(false),
+        })
+    }
+
+    /// Switches the cache into (or out of) offline mode. While offline, a
+    /// miss returns an error instead of calling `fetch`, so an application
+    /// can run entirely against a prepopulated database with no network
+    /// access.
+    pub fn set_offline(&self, offline: bool) {
+        self.cache_only.store(offline, Ordering::SeqCst);
+    }
+
+    /// Returns this thread's connection, opening and caching one if this is
+    /// the first call made from it. Each thread gets its own `Connection`
+    /// handle onto the same shared-cache database so that `ResponseCache`
+    /// can be used behind an `Arc` across tasks without serializing on a
+    /// single connection.
+    fn connection(&self) -> Result<&Connection> {
+        self.connections
+            .get_or_try(|| Self::open_connection(&self.path))
+    }
+
+    fn open_connection(path: &Path) -> Result<Connection> {
+        let uri = format!("file:{}?cache=shared", path.display());
+
+        let connection = Connection::open_with_flags(
+            uri,
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+
+        Self::init_schema(&connection)?;
+
+        Ok(connection)
+    }
 
+    fn init_schema(connection: &Connection) -> Result<()> {
         connection.execute(
-            "CREATE TABLE IF NOT EXISTS cached (key TEXT PRIMARY KEY, value BLOB);",
+            "CREATE TABLE IF NOT EXISTS cached (key TEXT PRIMARY KEY, value BLOB, inserted_at INTEGER, headers BLOB);",
             (),
         )?;
 
-        Ok(ResponseCache { connection })
+        // Databases created before TTL/HTTP-header support existed won't
+        // have these columns yet, so add them in place rather than forcing
+        // a fresh cache.
+        Self::add_column_if_missing(connection, "inserted_at", "INTEGER")?;
+        Self::add_column_if_missing(connection, "headers", "BLOB")?;
+
+        Ok(())
+    }
+
+    fn add_column_if_missing(connection: &Connection, column: &str, sql_type: &str) -> Result<()> {
+        let has_column = connection
+            .prepare("SELECT 1 FROM pragma_table_info('cached') WHERE name = (?1)")?
+            .exists(params![column])?;
+
+        if !has_column {
+            connection.execute(
+                &format!("ALTER TABLE cached ADD COLUMN {column} {sql_type};"),
+                (),
+            )?;
+        }
+
+        Ok(())
     }
 
     pub async fn get(
@@ -24,29 +149,477 @@ impl ResponseCache {
         key: impl AsRef<str>,
         fetch: impl Future<Output = eyre::Result<Vec<u8>>>,
     ) -> Result<Vec<u8>> {
-        let mut fetch_statement = self
-            .connection
-            .prepare("SELECT value FROM cached WHERE key = (?1)")?;
-
-        let mut query = fetch_statement.query([key.as_ref()]).unwrap();
-        let cache_row = query.next()?;
+        self.get_with_ttl(key, self.default_ttl, fetch).await
+    }
 
-        match cache_row {
-            Some(cache_row) => Ok(cache_row.get(0)?),
-            None => {
+    /// Round-trips any `T` through `serde_json` on top of the byte-level
+    /// `get`, so callers don't have to serialize by hand.
+    pub async fn get_typed<T>(
+        &self,
+        key: impl AsRef<str>,
+        fetch: impl Future<Output = eyre::Result<T>>,
+    ) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let bytes = self
+            .get(key, async {
                 let value = fetch.await?;
+                Ok(serde_json::to_vec(&value)?)
+            })
+            .await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Caches an HTTP response: persists the response headers alongside the
+    /// body and honors `Vary` the way the Cache API does. `request_headers`
+    /// are the headers of the incoming request. If the cached response
+    /// carries a `Vary` header, each distinct combination of the headers it
+    /// names (e.g. `Accept-Encoding: gzip` vs. `br`) is stored and looked up
+    /// as its own variant, rather than one request's variant overwriting
+    /// another's.
+    pub async fn get_response(
+        &self,
+        key: impl AsRef<str>,
+        request_headers: &[(String, String)],
+        fetch: impl Future<Output = eyre::Result<(Vec<(String, String)>, Vec<u8>)>>,
+    ) -> Result<(Vec<(String, String)>, Vec<u8>)> {
+        let key = key.as_ref();
+
+        // The `Vary` header names a response was stored with are only known
+        // once we've fetched it at least once; until then there's nothing
+        // to look up.
+        let known_vary_names = self
+            .lookup(&vary_directory_key(key))?
+            .map(|(bytes, _)| serde_json::from_slice::<Vec<String>>(&bytes))
+            .transpose()?;
+
+        let stored_variant_key =
+            known_vary_names.map(|vary_names| variant_key(key, &vary_names, request_headers));
+
+        let cache_only = self.cache_only.load(Ordering::SeqCst);
+
+        if let Some(stored_variant_key) = &stored_variant_key {
+            if let Some((value, inserted_at, Some(headers_bytes))) =
+                self.lookup_with_headers(stored_variant_key)?
+            {
+                let is_expired = match (inserted_at, self.default_ttl) {
+                    (Some(inserted_at), Some(ttl)) => {
+                        now_unix_secs() - inserted_at >= ttl.as_secs() as i64
+                    }
+                    _ => false,
+                };
+
+                // Offline, a stale entry still beats an error: there's no
+                // fetch to fall back on, so serve what's on disk.
+                if !is_expired || cache_only {
+                    return Ok((serde_json::from_slice(&headers_bytes)?, value));
+                }
+            }
+        }
 
-                self.connection.execute(
-                    "INSERT INTO cached VALUES((?1), (?2))",
-                    params![key.as_ref(), value],
-                )?;
+        if cache_only {
+            return Err(eyre::eyre!(
+                "cache miss for key {key:?} while offline (cache-only mode)"
+            ));
+        }
+
+        // Coalesce concurrent misses on the same variant. A cold key with no
+        // recorded `Vary` yet can't have its real variant key computed until
+        // the first response lands and names which headers it varies on, so
+        // it coalesces on a key derived from all of this request's headers
+        // instead. That undercounts how much two such callers could share
+        // (two requests racing on a cold key that differ only in headers the
+        // response turns out not to vary on each run their own fetch), but it
+        // never conflates callers whose headers differ, which
+        // is the case that would otherwise hand one caller another's body.
+        let coalesce_key =
+            stored_variant_key.unwrap_or_else(|| cold_coalesce_key(key, request_headers));
+
+        self.fetch_response_coalesced(&coalesce_key, key, request_headers, fetch)
+            .await
+    }
+
+    async fn fetch_response_coalesced(
+        &self,
+        coalesce_key: &str,
+        key: &str,
+        request_headers: &[(String, String)],
+        fetch: impl Future<Output = eyre::Result<(Vec<(String, String)>, Vec<u8>)>>,
+    ) -> Result<(Vec<(String, String)>, Vec<u8>)> {
+        let mut waiter = {
+            let mut in_flight = self.response_in_flight.lock().await;
+
+            match in_flight.get(coalesce_key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    let (sender, _) = broadcast::channel(1);
+                    in_flight.insert(coalesce_key.to_string(), sender);
+                    None
+                }
+            }
+        };
+
+        if let Some(waiter) = waiter.take() {
+            return Self::await_shared_response(waiter).await;
+        }
+
+        let result = self
+            .fetch_and_store_response(key, request_headers, fetch)
+            .await;
+
+        let shared_result: SharedResponseResult = match &result {
+            Ok(value) => Ok(Arc::new(value.clone())),
+            Err(err) => Err(Arc::from(err.to_string())),
+        };
+
+        let sender = self.response_in_flight.lock().await.remove(coalesce_key);
+        if let Some(sender) = sender {
+            let _ = sender.send(shared_result);
+        }
+
+        result
+    }
+
+    async fn fetch_and_store_response(
+        &self,
+        key: &str,
+        request_headers: &[(String, String)],
+        fetch: impl Future<Output = eyre::Result<(Vec<(String, String)>, Vec<u8>)>>,
+    ) -> Result<(Vec<(String, String)>, Vec<u8>)> {
+        let _permit = self.write_permits.acquire().await?;
+        let (response_headers, value) = fetch.await?;
+
+        let vary_names = vary_header_names(&response_headers);
+        let stored_variant_key = variant_key(key, &vary_names, request_headers);
+        let headers_bytes = serde_json::to_vec(&response_headers)?;
+        let inserted_at = now_unix_secs();
+
+        self.insert_with_headers_and_retry(
+            &stored_variant_key,
+            &value,
+            inserted_at,
+            Some(&headers_bytes),
+        )
+        .await?;
+        self.insert_with_headers_and_retry(
+            &vary_directory_key(key),
+            &serde_json::to_vec(&vary_names)?,
+            inserted_at,
+            None,
+        )
+        .await?;
+
+        Ok((response_headers, value))
+    }
+
+    async fn await_shared_response(
+        mut waiter: broadcast::Receiver<SharedResponseResult>,
+    ) -> Result<(Vec<(String, String)>, Vec<u8>)> {
+        match waiter.recv().await {
+            Ok(Ok(value)) => Ok((*value).clone()),
+            Ok(Err(message)) => Err(eyre::eyre!(message.to_string())),
+            Err(_) => Err(eyre::eyre!("in-flight fetch was dropped before completing")),
+        }
+    }
+
+    /// Entries older than `ttl` are treated as misses and re-fetched,
+    /// overriding the cache's default TTL for this call. Pass `None` to
+    /// cache forever. [`ResponseCache::get`] calls this with the cache's
+    /// default TTL, which is `None` for a cache opened with
+    /// [`ResponseCache::new`].
+    pub async fn get_with_ttl(
+        &self,
+        key: impl AsRef<str>,
+        ttl: Option<Duration>,
+        fetch: impl Future<Output = eyre::Result<Vec<u8>>>,
+    ) -> Result<Vec<u8>> {
+        let cached = self.lookup(key.as_ref())?;
+
+        let is_expired = match (&cached, ttl) {
+            (Some((_, Some(inserted_at))), Some(ttl)) => {
+                now_unix_secs() - inserted_at >= ttl.as_secs() as i64
+            }
+            _ => false,
+        };
+
+        // Offline, a stale entry still beats an error: there's no fetch to
+        // fall back on, so serve what's on disk.
+        let cache_only = self.cache_only.load(Ordering::SeqCst);
+
+        match cached {
+            Some((value, _)) if !is_expired || cache_only => Ok(value),
+            _ => self.fetch_coalesced(key.as_ref(), fetch).await,
+        }
+    }
+
+    /// Coalesces concurrent misses on the same key into a single `fetch`.
+    /// The first caller for a key runs `fetch` and writes the result; any
+    /// caller that arrives while that's in flight instead subscribes to the
+    /// broadcast of its outcome instead of issuing a fetch of its own. The
+    /// in-flight slot is cleared once the fetch finishes, so a caller that
+    /// reaches it after that runs a fetch of its own.
+    async fn fetch_coalesced(
+        &self,
+        key: &str,
+        fetch: impl Future<Output = eyre::Result<Vec<u8>>>,
+    ) -> Result<Vec<u8>> {
+        let mut waiter = {
+            let mut in_flight = self.in_flight.lock().await;
+
+            match in_flight.get(key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    let (sender, _) = broadcast::channel(1);
+                    in_flight.insert(key.to_string(), sender);
+                    None
+                }
+            }
+        };
+
+        if let Some(waiter) = waiter.take() {
+            return Self::await_shared_result(waiter).await;
+        }
+
+        // Bound how many misses fan out into concurrent fetch/insert work at
+        // once; excess callers simply wait their turn.
+        let result = if self.cache_only.load(Ordering::SeqCst) {
+            Err(eyre::eyre!(
+                "cache miss for key {key:?} while offline (cache-only mode)"
+            ))
+        } else {
+            let _permit = self.write_permits.acquire().await?;
+
+            let outcome = fetch.await;
+
+            match outcome {
+                Ok(value) => self
+                    .insert_with_retry(key, &value, now_unix_secs())
+                    .await
+                    .map(|()| value),
+                Err(err) => Err(err),
+            }
+        };
+
+        let shared_result: SharedFetchResult = match &result {
+            Ok(value) => Ok(Arc::new(value.clone())),
+            Err(err) => Err(Arc::from(err.to_string())),
+        };
+
+        let sender = self.in_flight.lock().await.remove(key);
+        if let Some(sender) = sender {
+            // No one may be listening if every waiter already gave up; that's fine.
+            let _ = sender.send(shared_result);
+        }
+
+        result
+    }
+
+    async fn await_shared_result(
+        mut waiter: broadcast::Receiver<SharedFetchResult>,
+    ) -> Result<Vec<u8>> {
+        match waiter.recv().await {
+            Ok(Ok(value)) => Ok((*value).clone()),
+            Ok(Err(message)) => Err(eyre::eyre!(message.to_string())),
+            Err(_) => Err(eyre::eyre!("in-flight fetch was dropped before completing")),
+        }
+    }
+
+    fn lookup(&self, key: &str) -> Result<Option<(Vec<u8>, Option<i64>)>> {
+        Ok(self
+            .lookup_with_headers(key)?
+            .map(|(value, inserted_at, _)| (value, inserted_at)))
+    }
+
+    fn lookup_with_headers(&self, key: &str) -> Result<Option<CachedRow>> {
+        let connection = self.connection()?;
+
+        let mut fetch_statement = connection
+            .prepare("SELECT value, inserted_at, headers FROM cached WHERE key = (?1)")?;
+
+        let mut query = fetch_statement.query([key])?;
+
+        match query.next()? {
+            Some(cache_row) => {
+                let value: Vec<u8> = cache_row.get(0)?;
+                let inserted_at: Option<i64> = cache_row.get(1)?;
+                let headers: Option<Vec<u8>> = cache_row.get(2)?;
+                Ok(Some((value, inserted_at, headers)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn insert_with_retry(&self, key: &str, value: &[u8], inserted_at: i64) -> Result<()> {
+        self.insert_with_headers_and_retry(key, value, inserted_at, None)
+            .await
+    }
+
+    async fn insert_with_headers_and_retry(
+        &self,
+        key: &str,
+        value: &[u8],
+        inserted_at: i64,
+        headers: Option<&[u8]>,
+    ) -> Result<()> {
+        for attempt in 0.. {
+            // Re-borrow the connection each attempt rather than holding it
+            // across the retry sleep below: `Connection` is `Send` but not
+            // `Sync`, so a `&Connection` held across an `.await` point would
+            // make this (and every caller awaiting it) non-`Send`.
+            let result = self.connection()?.execute(
+                "INSERT OR REPLACE INTO cached (key, value, inserted_at, headers) VALUES ((?1), (?2), (?3), (?4))",
+                params![key, value, inserted_at, headers],
+            );
+
+            match result {
+                Ok(_) => return Ok(()),
+                Err(rusqlite::Error::SqliteFailure(err, _))
+                    if err.code == ErrorCode::DatabaseLocked && attempt < MAX_LOCK_RETRIES =>
+                {
+                    tokio::time::sleep(Duration::from_millis(10 * 2u64.pow(attempt))).await;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        unreachable!("loop only exits via return")
+    }
+
+    /// Deletes every entry older than the cache's configured TTL, so callers
+    /// can reclaim space without waiting for a `get` to notice the entry is
+    /// stale. A no-op on a cache with no TTL configured, since nothing is
+    /// ever considered expired.
+    pub fn purge_expired(&self) -> Result<()> {
+        let Some(ttl) = self.default_ttl else {
+            return Ok(());
+        };
+
+        let cutoff = now_unix_secs() - ttl.as_secs() as i64;
+
+        self.connection()?.execute(
+            "DELETE FROM cached WHERE inserted_at IS NOT NULL AND inserted_at <= (?1)",
+            params![cutoff],
+        )?;
+
+        Ok(())
+    }
+
+    /// Never makes a caller wait on the network: if a (possibly expired)
+    /// entry is present, it's returned immediately and, if stale, a refresh
+    /// is kicked off in the background to update it for next time. Only an
+    /// outright miss blocks on `fetch`, falling back to
+    /// [`ResponseCache::get_with_ttl`]. Requires `self` behind an `Arc`
+    /// since the refresh can outlive this call.
+    pub async fn get_stale_while_revalidate(
+        self: &Arc<Self>,
+        key: impl AsRef<str>,
+        ttl: Duration,
+        fetch: impl Future<Output = eyre::Result<Vec<u8>>> + Send + 'static,
+    ) -> Result<Vec<u8>> {
+        let key = key.as_ref().to_string();
+
+        match self.lookup(&key)? {
+            Some((value, inserted_at)) => {
+                let is_stale = match inserted_at {
+                    Some(inserted_at) => now_unix_secs() - inserted_at >= ttl.as_secs() as i64,
+                    None => false,
+                };
+
+                if is_stale && !self.cache_only.load(Ordering::SeqCst) {
+                    // Refresh through the same single-flight/permit-bounded
+                    // path `get` uses, so a popular stale key doesn't spawn
+                    // an unbounded, un-deduplicated fetch per caller.
+                    let cache = Arc::clone(self);
+                    tokio::spawn(async move {
+                        let _ = cache.fetch_coalesced(&key, fetch).await;
+                    });
+                }
 
                 Ok(value)
             }
+            None => self.get_with_ttl(key, Some(ttl), fetch).await,
         }
     }
 }
 
+fn now_unix_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the Unix epoch")
+        .as_secs() as i64
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Extracts and normalizes the header names a response's `Vary` header
+/// names, lowercased and sorted so two equivalent `Vary` values always
+/// produce the same variant key. A response with no `Vary` header varies
+/// on nothing, so it gets a single shared variant.
+fn vary_header_names(response_headers: &[(String, String)]) -> Vec<String> {
+    let Some(vary) = header_value(response_headers, "vary") else {
+        return Vec::new();
+    };
+
+    let mut names: Vec<String> = vary
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// The key under which a response's `Vary` header names are recorded, so
+/// that a later request can compute the same variant key without first
+/// fetching.
+fn vary_directory_key(key: &str) -> String {
+    format!("{key}\u{0}vary-directory")
+}
+
+/// The key under which one specific Vary-distinguished response variant is
+/// stored: `key` plus the request header values named by `vary_names`, so
+/// e.g. `Accept-Encoding: gzip` and `Accept-Encoding: br` responses for the
+/// same `key` live in separate rows instead of overwriting each other.
+fn variant_key(key: &str, vary_names: &[String], request_headers: &[(String, String)]) -> String {
+    let variant = vary_names
+        .iter()
+        .map(|name| {
+            format!(
+                "{name}={}",
+                header_value(request_headers, name).unwrap_or("")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\u{1}");
+
+    format!("{key}\u{0}variant\u{0}{variant}")
+}
+
+/// The key a cold `get_response` miss coalesces concurrent callers on before
+/// a response (and thus its `Vary` names) has ever been fetched. It folds in
+/// every one of the request's headers, not just the ones that turn out to
+/// matter, so two concurrent callers are only coalesced onto the same
+/// in-flight fetch if their requests were identical; callers with differing
+/// headers each get their own fetch rather than one handing its response to
+/// the other.
+fn cold_coalesce_key(key: &str, request_headers: &[(String, String)]) -> String {
+    let mut headers: Vec<String> = request_headers
+        .iter()
+        .map(|(name, value)| format!("{}={value}", name.to_ascii_lowercase()))
+        .collect();
+    headers.sort();
+
+    format!("{key}\u{0}cold\u{0}{}", headers.join("\u{1}"))
+}
+
 #[cfg(test)]
 mod test {
     use tempfile::NamedTempFile;
@@ -151,4 +724,481 @@ mod test {
 
         assert_eq!(uuid::Uuid::from_slice(&fetched_data).unwrap(), data);
     }
+
+    #[tokio::test]
+    async fn expires_after_ttl() {
+        use std::time::Duration;
+
+        let file = NamedTempFile::new().unwrap();
+        let cache = ResponseCache::with_ttl(file.path(), Duration::from_secs(0)).unwrap();
+
+        let mut fetched_count = 0;
+
+        for _ in 0..3 {
+            cache
+                .get("1", async {
+                    fetched_count += 1;
+                    Ok("1".as_bytes().to_vec())
+                })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(3, fetched_count);
+    }
+
+    #[tokio::test]
+    async fn purge_expired_removes_old_entries() {
+        use std::time::Duration;
+
+        let file = NamedTempFile::new().unwrap();
+        let cache = ResponseCache::with_ttl(file.path(), Duration::from_secs(0)).unwrap();
+
+        cache
+            .get("1", async { Ok("1".as_bytes().to_vec()) })
+            .await
+            .unwrap();
+
+        cache.purge_expired().unwrap();
+
+        let mut fetched_count = 0;
+        cache
+            .get("1", async {
+                fetched_count += 1;
+                Ok("1".as_bytes().to_vec())
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(1, fetched_count);
+    }
+
+    #[tokio::test]
+    async fn shared_across_tasks() {
+        use std::sync::Arc;
+
+        let file = NamedTempFile::new().unwrap();
+        let cache = Arc::new(ResponseCache::new(file.path()).unwrap());
+
+        let mut tasks = Vec::new();
+        for i in 0..8 {
+            let cache = Arc::clone(&cache);
+            tasks.push(tokio::spawn(async move {
+                let key = i.to_string();
+                cache
+                    .get(&key, async { Ok(key.clone().into_bytes()) })
+                    .await
+                    .unwrap()
+            }));
+        }
+
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await.unwrap(), i.to_string().into_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn dedupes_concurrent_misses() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use std::time::Duration;
+
+        let file = NamedTempFile::new().unwrap();
+        let cache = Arc::new(ResponseCache::new(file.path()).unwrap());
+        let fetch_count = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let cache = Arc::clone(&cache);
+            let fetch_count = Arc::clone(&fetch_count);
+            tasks.push(tokio::spawn(async move {
+                cache
+                    .get("dedup", async {
+                        fetch_count.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        Ok("value".as_bytes().to_vec())
+                    })
+                    .await
+                    .unwrap()
+            }));
+        }
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "value".as_bytes().to_vec());
+        }
+
+        assert_eq!(1, fetch_count.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn round_trips_typed_value() {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+        struct Widget {
+            name: String,
+            count: u32,
+        }
+
+        let test = TestHarness::new();
+
+        let widget = Widget {
+            name: "sprocket".to_string(),
+            count: 3,
+        };
+
+        let fetched: Widget = test
+            .cache
+            .get_typed("widget", async { Ok(widget.clone()) })
+            .await
+            .unwrap();
+        assert_eq!(fetched, widget);
+
+        let cached: Widget = test
+            .cache
+            .get_typed("widget", async { panic!() })
+            .await
+            .unwrap();
+        assert_eq!(cached, widget);
+    }
+
+    #[tokio::test]
+    async fn caches_response_headers_and_body() {
+        let test = TestHarness::new();
+
+        let headers = vec![("content-type".to_string(), "text/plain".to_string())];
+        let body = "hello".as_bytes().to_vec();
+
+        let (fetched_headers, fetched_body) = test
+            .cache
+            .get_response("page", &[], {
+                let headers = headers.clone();
+                let body = body.clone();
+                async move { Ok((headers, body)) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(fetched_headers, headers);
+        assert_eq!(fetched_body, body);
+
+        let (cached_headers, cached_body) = test
+            .cache
+            .get_response("page", &[], async { panic!() })
+            .await
+            .unwrap();
+        assert_eq!(cached_headers, headers);
+        assert_eq!(cached_body, body);
+    }
+
+    #[tokio::test]
+    async fn vary_header_misses_on_differing_request_header() {
+        let test = TestHarness::new();
+
+        let accept_encoding =
+            |value: &str| vec![("accept-encoding".to_string(), value.to_string())];
+        let headers_with_vary = vec![("vary".to_string(), "Accept-Encoding".to_string())];
+
+        test.cache
+            .get_response("page", &accept_encoding("gzip"), {
+                let headers = headers_with_vary.clone();
+                async move { Ok((headers, "gzip-body".as_bytes().to_vec())) }
+            })
+            .await
+            .unwrap();
+
+        let (headers, body) = test
+            .cache
+            .get_response("page", &accept_encoding("br"), {
+                let headers = headers_with_vary.clone();
+                async move { Ok((headers, "br-body".as_bytes().to_vec())) }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(headers, headers_with_vary);
+        assert_eq!(body, "br-body".as_bytes().to_vec());
+    }
+
+    #[tokio::test]
+    async fn vary_stores_each_variant_instead_of_thrashing() {
+        let test = TestHarness::new();
+
+        let accept_encoding =
+            |value: &str| vec![("accept-encoding".to_string(), value.to_string())];
+        let headers_with_vary = vec![("vary".to_string(), "Accept-Encoding".to_string())];
+
+        for (encoding, body) in [("gzip", "gzip-body"), ("br", "br-body")] {
+            test.cache
+                .get_response("page", &accept_encoding(encoding), {
+                    let headers = headers_with_vary.clone();
+                    let body = body.as_bytes().to_vec();
+                    async move { Ok((headers, body)) }
+                })
+                .await
+                .unwrap();
+        }
+
+        // Both variants are now cached; alternating between them must not
+        // re-fetch, since each has its own stored entry.
+        let (_, gzip_body) = test
+            .cache
+            .get_response("page", &accept_encoding("gzip"), async { panic!() })
+            .await
+            .unwrap();
+        assert_eq!(gzip_body, "gzip-body".as_bytes().to_vec());
+
+        let (_, br_body) = test
+            .cache
+            .get_response("page", &accept_encoding("br"), async { panic!() })
+            .await
+            .unwrap();
+        assert_eq!(br_body, "br-body".as_bytes().to_vec());
+    }
+
+    #[tokio::test]
+    async fn get_response_expires_after_ttl() {
+        use std::time::Duration;
+
+        let file = NamedTempFile::new().unwrap();
+        let cache = ResponseCache::with_ttl(file.path(), Duration::from_secs(0)).unwrap();
+
+        let mut fetched_count = 0;
+
+        for _ in 0..3 {
+            cache
+                .get_response("page", &[], async {
+                    fetched_count += 1;
+                    Ok((Vec::new(), "body".as_bytes().to_vec()))
+                })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(3, fetched_count);
+    }
+
+    #[tokio::test]
+    async fn get_response_dedupes_concurrent_misses() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use std::time::Duration;
+
+        let file = NamedTempFile::new().unwrap();
+        let cache = Arc::new(ResponseCache::new(file.path()).unwrap());
+        let fetch_count = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let cache = Arc::clone(&cache);
+            let fetch_count = Arc::clone(&fetch_count);
+            tasks.push(tokio::spawn(async move {
+                cache
+                    .get_response("page", &[], async {
+                        fetch_count.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        Ok((Vec::new(), "body".as_bytes().to_vec()))
+                    })
+                    .await
+                    .unwrap()
+            }));
+        }
+
+        for task in tasks {
+            let (_, body) = task.await.unwrap();
+            assert_eq!(body, "body".as_bytes().to_vec());
+        }
+
+        assert_eq!(1, fetch_count.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn concurrent_cold_misses_with_differing_vary_headers_dont_cross_wires() {
+        use std::time::Duration;
+
+        let test = TestHarness::new();
+
+        let accept_encoding =
+            |value: &str| vec![("accept-encoding".to_string(), value.to_string())];
+        let headers_with_vary = vec![("vary".to_string(), "Accept-Encoding".to_string())];
+
+        let gzip_headers = accept_encoding("gzip");
+        let br_headers = accept_encoding("br");
+
+        // Both requests race on the same cold key, which hasn't recorded a
+        // `Vary` yet, so their coalesce key can't be the real variant key.
+        // Neither request should ever observe the other's body.
+        let gzip = test.cache.get_response("page", &gzip_headers, {
+            let headers = headers_with_vary.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok((headers, "gzip-body".as_bytes().to_vec()))
+            }
+        });
+        let br = test.cache.get_response("page", &br_headers, {
+            let headers = headers_with_vary.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok((headers, "br-body".as_bytes().to_vec()))
+            }
+        });
+
+        let (gzip_result, br_result) = tokio::join!(gzip, br);
+
+        assert_eq!(gzip_result.unwrap().1, "gzip-body".as_bytes().to_vec());
+        assert_eq!(br_result.unwrap().1, "br-body".as_bytes().to_vec());
+    }
+
+    #[tokio::test]
+    async fn offline_mode_serves_cached_values_and_errors_on_miss() {
+        let test = TestHarness::new();
+
+        test.cache
+            .get("1", async { Ok("1".as_bytes().to_vec()) })
+            .await
+            .unwrap();
+
+        test.cache.set_offline(true);
+
+        assert_eq!(
+            test.cache.get("1", async { panic!() }).await.unwrap(),
+            "1".as_bytes().to_vec()
+        );
+
+        assert!(test.cache.get("2", async { panic!() }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn offline_mode_serves_expired_entries_instead_of_erroring() {
+        use std::time::Duration;
+
+        let file = NamedTempFile::new().unwrap();
+        let cache = ResponseCache::with_ttl(file.path(), Duration::from_secs(0)).unwrap();
+
+        cache
+            .get("1", async { Ok("1".as_bytes().to_vec()) })
+            .await
+            .unwrap();
+
+        cache.set_offline(true);
+
+        assert_eq!(
+            cache.get("1", async { panic!() }).await.unwrap(),
+            "1".as_bytes().to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn offline_mode_serves_expired_response_instead_of_erroring() {
+        use std::time::Duration;
+
+        let file = NamedTempFile::new().unwrap();
+        let cache = ResponseCache::with_ttl(file.path(), Duration::from_secs(0)).unwrap();
+
+        let headers = vec![("content-type".to_string(), "text/plain".to_string())];
+        let body = "hello".as_bytes().to_vec();
+
+        cache
+            .get_response("page", &[], {
+                let headers = headers.clone();
+                let body = body.clone();
+                async move { Ok((headers, body)) }
+            })
+            .await
+            .unwrap();
+
+        cache.set_offline(true);
+
+        let (cached_headers, cached_body) = cache
+            .get_response("page", &[], async { panic!() })
+            .await
+            .unwrap();
+        assert_eq!(cached_headers, headers);
+        assert_eq!(cached_body, body);
+    }
+
+    #[tokio::test]
+    async fn stale_while_revalidate_returns_immediately_and_refreshes() {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let file = NamedTempFile::new().unwrap();
+        let cache = Arc::new(ResponseCache::new(file.path()).unwrap());
+
+        cache
+            .get("1", async { Ok("stale".as_bytes().to_vec()) })
+            .await
+            .unwrap();
+
+        let value = cache
+            .get_stale_while_revalidate("1", Duration::from_secs(0), async {
+                Ok("fresh".as_bytes().to_vec())
+            })
+            .await
+            .unwrap();
+        assert_eq!(value, "stale".as_bytes().to_vec());
+
+        // Give the background refresh a chance to land before checking.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let refreshed = cache
+            .get_with_ttl("1", None, async { panic!() })
+            .await
+            .unwrap();
+        assert_eq!(refreshed, "fresh".as_bytes().to_vec());
+    }
+
+    #[tokio::test]
+    async fn stale_while_revalidate_refresh_is_spawnable_across_tasks() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use std::time::Duration;
+
+        // The background refresh is handed to `tokio::spawn` internally, so
+        // the whole call must be `Send`. Driving it from inside an explicit
+        // `tokio::spawn` here as well as exercises that bound at the call
+        // site too, not just inside the library.
+        let file = NamedTempFile::new().unwrap();
+        let cache = Arc::new(ResponseCache::new(file.path()).unwrap());
+        let fetch_count = Arc::new(AtomicUsize::new(0));
+
+        cache
+            .get("1", async { Ok("stale".as_bytes().to_vec()) })
+            .await
+            .unwrap();
+
+        let mut tasks = Vec::new();
+        for _ in 0..4 {
+            let cache = Arc::clone(&cache);
+            let fetch_count = Arc::clone(&fetch_count);
+            tasks.push(tokio::spawn(async move {
+                cache
+                    .get_stale_while_revalidate("1", Duration::from_secs(0), async move {
+                        fetch_count.fetch_add(1, Ordering::SeqCst);
+                        Ok("fresh".as_bytes().to_vec())
+                    })
+                    .await
+                    .unwrap()
+            }));
+        }
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "stale".as_bytes().to_vec());
+        }
+
+        // Give the background refreshes a chance to land before checking.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let refreshed = cache
+            .get_with_ttl("1", None, async { panic!() })
+            .await
+            .unwrap();
+        assert_eq!(refreshed, "fresh".as_bytes().to_vec());
+        assert!(fetch_count.load(Ordering::SeqCst) >= 1);
+    }
 }